@@ -31,20 +31,38 @@
 //!
 //! will download the serde package specified in the
 //! dependency section to the `target/patch` folder
-//! and apply the given patches. To use the patched
-//! version one has to override the dependency using
-//! `replace` like this
+//! and apply the given patches. It also writes (and
+//! keeps up to date) the required override in
+//! `Cargo.toml`
 //!
 //! ```toml
 //! [patch.crates-io]
 //! serde = { path = './target/patch/serde-1.0.110' }
 //! ```
 //!
+//! so `cargo build` picks up the patched dependency
+//! without any manual editing.
+//!
+//! This also works for dependencies that do not come
+//! from crates.io. Add a `source` to disambiguate which
+//! resolved package to patch, and the matching override
+//! table is written automatically:
+//!
+//! ```toml
+//! [package.metadata.patch.foo]
+//! source = "https://github.com/foo/bar"
+//! patches = [
+//!     "foo.patch"
+//! ]
+//! ```
+//!
 //! # Patch format
 //!
 //! You can either use [diff](http://man7.org/linux/man-pages/man1/diff.1.html) or
 //! [git](https://linux.die.net/man/1/git) to create patch files. Important is that
-//! file paths are relativ and inside the dependency
+//! file paths are relativ and inside the dependency. `git diff` output
+//! that creates, deletes or renames a file is also supported; binary
+//! hunks are skipped with a warning since they cannot be applied.
 //!
 //! # Limitations
 //!
@@ -62,7 +80,7 @@ use cargo::{
         registry::PackageRegistry,
         resolver::{features::CliFeatures, HasDevUnits},
         shell::Verbosity,
-        PackageId, Resolve, Workspace,
+        PackageId, Resolve, SourceId, Workspace,
     },
     ops::{get_resolved_packages, load_pkg_lockfile, resolve_with_previous},
     util::{config::Config, important_paths::find_root_manifest_for_wd},
@@ -71,27 +89,64 @@ use failure::err_msg;
 use fs_extra::dir::{copy, CopyOptions};
 use patch::{Line, Patch};
 use semver::VersionReq;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
+    collections::hash_map::DefaultHasher,
     fs,
+    hash::{Hash, Hasher},
     io::ErrorKind,
     path::{Path, PathBuf},
 };
 
-use toml_edit::easy::Value;
+use toml_edit::{easy::Value, Document, InlineTable, Item, Table};
 use regex::Regex;
 
 #[derive(Debug, Clone)]
 struct PatchEntry {
     name: String,
     version: Option<VersionReq>,
+    /// Which source to match the dependency against: `"crates-io"`, an
+    /// alternate registry URL, or a git repository URL. Left unset, the
+    /// first resolved dependency with a matching name/version is used,
+    /// regardless of where it came from.
+    source: Option<String>,
     patches: Vec<PathBuf>,
 }
 const RANGE_REGEX: &str = r"(?m)^(?P<rangeBegin>@@ -[0-9]+,[0-9]+ \+[0-9]+)(?P<rangeEnd>,[0-9]+)? @@.*\n";
 const RANGE_REPLACE: &str = "$rangeBegin$rangeEnd @@\n";
 
+/// Marks the start of a per-file section in a `git diff`-formatted patch.
+const GIT_DIFF_SECTION_REGEX: &str = r"(?m)^diff --git ";
+const RENAME_FROM_REGEX: &str = r"(?m)^rename from (?P<path>.+)$";
+const RENAME_TO_REGEX: &str = r"(?m)^rename to (?P<path>.+)$";
+
+/// The `[patch.<source>]` table cargo consults for crates.io dependencies.
+const CRATES_IO_SOURCE: &str = "crates-io";
+
+/// How far (in lines) to search away from a hunk's recorded position before
+/// giving up, mirroring the default search radius of GNU `patch`.
+const DEFAULT_MAX_OFFSET: usize = 1000;
+/// How many leading/trailing context lines may be dropped from the match
+/// requirement when an exact match cannot be found, mirroring `patch -F`.
+const DEFAULT_FUZZ: usize = 2;
+
+/// Where the state of the last successful run is recorded, so that
+/// subsequent runs can skip dependencies that have not changed.
+const APPLIED_STATE_PATH: &str = "target/patch/.applied.json";
+
+/// What was applied for a single patched dependency on the last run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AppliedPatch {
+    version: String,
+    patch_hashes: Vec<(PathBuf, u64)>,
+}
+
+type AppliedState = HashMap<String, AppliedPatch>;
+
 #[allow(clippy::wildcard_enum_match_arm)]
-fn clear_patch_folder() -> Result<()> {
-    match fs::remove_dir_all("target/patch") {
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
         Ok(_) => Ok(()),
         Err(err) => match err.kind() {
             ErrorKind::NotFound => Ok(()),
@@ -100,6 +155,36 @@ fn clear_patch_folder() -> Result<()> {
     }
 }
 
+fn load_applied_state() -> AppliedState {
+    fs::read_to_string(APPLIED_STATE_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_applied_state(state: &AppliedState) -> Result<()> {
+    fs::create_dir_all("target/patch/")?;
+    let data = serde_json::to_string_pretty(state)?;
+    fs::write(APPLIED_STATE_PATH, data)?;
+    Ok(())
+}
+
+/// Hashes a patch file's contents so that a later run can tell whether it
+/// has changed since it was last applied.
+fn hash_patch_file(path: &Path) -> Result<u64> {
+    let data = read_to_string(path)?;
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn hash_patch_files(patches: &[PathBuf]) -> Result<Vec<(PathBuf, u64)>> {
+    patches
+        .iter()
+        .map(|path| hash_patch_file(path).map(|hash| (path.clone(), hash)))
+        .collect()
+}
+
 fn setup_config() -> Result<Config> {
     let config = Config::default()?;
     config.shell().set_verbosity(Verbosity::Quiet);
@@ -165,6 +250,13 @@ fn parse_patch_entry(name: &str, entry: &Value) -> Option<PatchEntry> {
         }
         value
     });
+    let source = entry.get("source").and_then(|e| {
+        let value = e.as_str().map(str::to_owned);
+        if value.is_none() {
+            eprintln!("Source must be a string: {}", e);
+        }
+        value
+    });
     let patches = entry
         .get("patches")
         .and_then(Value::as_array)
@@ -172,6 +264,7 @@ fn parse_patch_entry(name: &str, entry: &Value) -> Option<PatchEntry> {
     Some(PatchEntry {
         name: name.to_owned(),
         version,
+        source,
         patches,
     })
 }
@@ -189,6 +282,17 @@ fn parse_patches(entries: &[Value]) -> Vec<PathBuf> {
         .collect()
 }
 
+/// Whether `source_id` is the one a `PatchEntry`'s `source` field names,
+/// accepting `"crates-io"` as an alias for the crates.io registry in
+/// addition to matching git and alternate-registry URLs verbatim.
+fn source_id_matches(source_id: SourceId, spec: &str) -> bool {
+    if spec == CRATES_IO_SOURCE {
+        source_id.is_crates_io()
+    } else {
+        source_id.url().as_str() == spec
+    }
+}
+
 fn get_ids(
     patches: Vec<PatchEntry>,
     resolve: &Resolve,
@@ -198,11 +302,12 @@ fn get_ids(
         for dep in resolve.iter() {
             if dep.name().as_str() == patch_entry.name
                 && patch_entry.version.as_ref().map_or(true, |ver| ver.matches(dep.version()))
+                && patch_entry.source.as_ref().map_or(true, |src| source_id_matches(dep.source_id(), src))
             {
                 if matched_dep.is_none() {
                     matched_dep = Some(dep);
                 } else {
-                    eprintln!("There are multiple versions of {} available. Try specifying a version.", patch_entry.name);
+                    eprintln!("There are multiple versions of {} available. Try specifying a version or source.", patch_entry.name);
                 }
             }
         }
@@ -213,66 +318,352 @@ fn get_ids(
     }).collect()
 }
 
-fn copy_package(pkg: &Package) -> Result<PathBuf> {
-    fs::create_dir_all("target/patch/")?;
-    let options = CopyOptions::new();
-    let _ = copy(pkg.root(), "target/patch/", &options)?;
-    if let Some(name) = pkg.root().file_name() {
-        let buf = PathBuf::from("target/patch/");
-        let buf = buf.join(name).canonicalize()?;
-        Ok(buf)
+/// The `[patch.<source>]` table key cargo uses for a given resolved source:
+/// the special alias `"crates-io"`, or the source's URL for anything else
+/// (git repositories, alternate registries).
+fn patch_table_key(source_id: SourceId) -> String {
+    if source_id.is_crates_io() {
+        CRATES_IO_SOURCE.to_owned()
     } else {
-        Err(err_msg("Dependency Folder does not have a name")
-            .compat()
-            .into())
+        source_id.url().to_string()
     }
 }
 
+/// Copies `pkg`'s source into `target_dir`, creating it if needed. The
+/// caller picks `target_dir`'s name rather than relying on `pkg.root()`'s:
+/// that's `name-version` for a registry source, but for git and path
+/// dependencies it's a checkout hash or the dependency's local folder name,
+/// which would no longer line up with the `name-version` `[patch]` override
+/// `main` writes into Cargo.toml.
+fn copy_package(pkg: &Package, target_dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(target_dir)?;
+    let mut options = CopyOptions::new();
+    options.content_only = true;
+    let _ = copy(pkg.root(), target_dir, &options)?;
+    Ok(target_dir.canonicalize()?)
+}
+
+/// Inserts or updates the `[patch.<source>]` override for each patched
+/// dependency in the workspace manifest, so that `cargo build` picks up the
+/// freshly copied `target/patch/<name>-<version>` directory without any
+/// manual `Cargo.toml` editing. Preserves existing formatting and comments.
+fn update_patch_overrides(manifest_path: &Path, source: &str, patched: &[(String, PathBuf)]) -> Result<()> {
+    if patched.is_empty() {
+        return Ok(());
+    }
+    let manifest_data = fs::read_to_string(manifest_path)?;
+    let mut document = manifest_data
+        .parse::<Document>()
+        .map_err(|_| err_msg("Unable to parse Cargo.toml").compat())?;
+
+    if document["patch"][source].is_none() {
+        document["patch"][source] = Item::Table(Table::new());
+    }
+    let patch_table = document["patch"][source]
+        .as_table_mut()
+        .ok_or_else(|| err_msg(format!("`[patch.{}]` in Cargo.toml is not a table", source)).compat())?;
+
+    for (name, path) in patched {
+        use toml_edit::Value as EditValue;
+        let mut dependency = InlineTable::default();
+        dependency.get_or_insert("path", path.to_string_lossy().into_owned());
+        patch_table[name.as_str()] = Item::Value(EditValue::InlineTable(dependency));
+    }
+
+    fs::write(manifest_path, document.to_string())?;
+    Ok(())
+}
+
 fn apply_patches(name: &str, patches: &[PathBuf], path: &Path) -> Result<()> {
-    let regex = Regex::new(RANGE_REGEX)?;
+    let range_regex = Regex::new(RANGE_REGEX)?;
+    let section_regex = Regex::new(GIT_DIFF_SECTION_REGEX)?;
+    let rename_from_regex = Regex::new(RENAME_FROM_REGEX)?;
+    let rename_to_regex = Regex::new(RENAME_TO_REGEX)?;
     for patch in patches {
         let data = read_to_string(patch)?;
-        let data = regex.replace_all(&data, RANGE_REPLACE);
-        let patches = Patch::from_multiple(&data)
-            .map_err(|_| err_msg("Unable to parse patch file").compat())?;
-        for patch in patches {
-            let file_path = path.to_owned();
-            let file_path = file_path.join(patch.old.path.as_ref());
-            let file_path = file_path.canonicalize()?;
-            if file_path.starts_with(&path) {
-                let data = read_to_string(&file_path)?;
-                let data = apply_patch(patch, &data);
-                fs::write(file_path, data)?;
-                println!("Patched {}", name);
-            } else {
+        let data = range_regex.replace_all(&data, RANGE_REPLACE);
+        for section in split_diff_sections(&data, &section_regex) {
+            apply_diff_section(name, section, path, &rename_from_regex, &rename_to_regex)?;
+        }
+    }
+    Ok(())
+}
+
+/// Splits a (possibly multi-file) diff into one chunk per `diff --git`
+/// header, so each file's rename/binary/create/delete metadata can be
+/// handled independently. A diff with no `diff --git` headers at all (a
+/// plain `diff -u`, which carries no such metadata) is returned whole.
+fn split_diff_sections<'a>(data: &'a str, marker: &Regex) -> Vec<&'a str> {
+    let starts: Vec<usize> = marker.find_iter(data).map(|m| m.start()).collect();
+    if starts.is_empty() {
+        return vec![data];
+    }
+    let mut sections: Vec<&str> = starts
+        .windows(2)
+        .map(|window| &data[window[0]..window[1]])
+        .collect();
+    if let Some(&last) = starts.last() {
+        sections.push(&data[last..]);
+    }
+    sections
+}
+
+fn is_binary_diff_section(section: &str) -> bool {
+    section
+        .lines()
+        .any(|line| line.starts_with("Binary files ") || line.starts_with("GIT binary patch"))
+}
+
+/// Returns the `rename from`/`rename to` paths recorded in a diff section,
+/// if it is (at least in part) a rename.
+fn renamed_paths(section: &str, from_regex: &Regex, to_regex: &Regex) -> Option<(String, String)> {
+    let from = from_regex.captures(section)?.name("path")?.as_str().to_owned();
+    let to = to_regex.captures(section)?.name("path")?.as_str().to_owned();
+    Some((from, to))
+}
+
+/// Strips the `a/`/`b/` prefix `git diff` puts on paths by default.
+fn strip_diff_prefix(diff_path: &str) -> &str {
+    diff_path
+        .strip_prefix("a/")
+        .or_else(|| diff_path.strip_prefix("b/"))
+        .unwrap_or(diff_path)
+}
+
+/// Resolves `diff_path` (relative to the dependency's `root`) without
+/// requiring the target to already exist, rejecting any path that would
+/// escape the dependency folder.
+fn resolve_target_path(root: &Path, diff_path: &str) -> Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let mut resolved = root.clone();
+    for component in Path::new(strip_diff_prefix(diff_path)).components() {
+        match component {
+            std::path::Component::Normal(part) => resolved.push(part),
+            std::path::Component::ParentDir => {
                 return Err(err_msg("Patch file tried to escape dependency folder")
                     .compat()
                     .into());
             }
+            std::path::Component::CurDir
+            | std::path::Component::RootDir
+            | std::path::Component::Prefix(_) => {}
+        }
+    }
+    if resolved.starts_with(&root) {
+        Ok(resolved)
+    } else {
+        Err(err_msg("Patch file tried to escape dependency folder")
+            .compat()
+            .into())
+    }
+}
+
+fn apply_rename(path: &Path, from: &str, to: &str) -> Result<()> {
+    let from_path = resolve_target_path(path, from)?;
+    let to_path = resolve_target_path(path, to)?;
+    if let Some(parent) = to_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from_path, to_path)?;
+    Ok(())
+}
+
+/// Applies one file's worth of a parsed diff, handling creation (`old` is
+/// `/dev/null`), deletion (`new` is `/dev/null`) and renames (`old` and
+/// `new` differ) in addition to an ordinary in-place edit.
+fn apply_single_file_diff(name: &str, diff: &Patch<'_>, path: &Path) -> Result<()> {
+    if diff.new.path.as_ref() == "/dev/null" {
+        let old_path = resolve_target_path(path, diff.old.path.as_ref())?;
+        fs::remove_file(&old_path)?;
+        println!("Deleted {} from {}", strip_diff_prefix(diff.old.path.as_ref()), name);
+        return Ok(());
+    }
+
+    let new_path = resolve_target_path(path, diff.new.path.as_ref())?;
+    let old_content = if diff.old.path.as_ref() == "/dev/null" {
+        String::new()
+    } else {
+        let old_path = resolve_target_path(path, diff.old.path.as_ref())?;
+        let content = read_to_string(&old_path)?;
+        if old_path != new_path {
+            fs::remove_file(&old_path)?;
+        }
+        content
+    };
+
+    if let Some(parent) = new_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let patched = apply_patch(diff, &old_content, &new_path, DEFAULT_MAX_OFFSET, DEFAULT_FUZZ)?;
+    fs::write(&new_path, patched)?;
+    println!("Patched {}", name);
+    Ok(())
+}
+
+fn apply_diff_section(
+    name: &str,
+    section: &str,
+    path: &Path,
+    rename_from_regex: &Regex,
+    rename_to_regex: &Regex,
+) -> Result<()> {
+    if is_binary_diff_section(section) {
+        eprintln!(
+            "Skipping binary patch in {} ({})",
+            name,
+            section.lines().next().unwrap_or(section).trim()
+        );
+        return Ok(());
+    }
+
+    if let Some((from, to)) = renamed_paths(section, rename_from_regex, rename_to_regex) {
+        if !section.contains("@@ ") {
+            apply_rename(path, &from, &to)?;
+            println!("Renamed {} to {} in {}", from, to, name);
+            return Ok(());
         }
     }
+
+    if !section.contains("--- ") || !section.contains("+++ ") {
+        // No content hunks to apply, e.g. a pure file-mode change.
+        return Ok(());
+    }
+
+    let diffs = Patch::from_multiple(section).map_err(|_| err_msg("Unable to parse patch file").compat())?;
+    for diff in diffs {
+        apply_single_file_diff(name, &diff, path)?;
+    }
     Ok(())
 }
 
-#[allow(
-    clippy::as_conversions,
-    clippy::indexing_slicing,
-    clippy::cast_possible_truncation
-)]
-fn apply_patch(diff: Patch<'_>, old: &str) -> String {
+/// The Context + Remove lines of a hunk, in order, i.e. the sequence of
+/// lines the hunk expects to find in the unpatched file, along with how many
+/// lines at the start and end of that sequence are Context (and therefore
+/// safe for fuzzy matching to trim). Remove lines are never eligible for
+/// trimming: dropping one from the match requirement would let `apply_patch`
+/// remove a line that was never actually verified against the file.
+struct HunkLines<'a> {
+    lines: Vec<&'a str>,
+    leading_context: usize,
+    trailing_context: usize,
+}
+
+fn hunk_from_lines<'a>(hunk: &patch::Hunk<'a>) -> HunkLines<'a> {
+    let tagged: Vec<(bool, &str)> = hunk
+        .lines
+        .iter()
+        .filter_map(|line| match line {
+            Line::Context(s) => Some((true, *s)),
+            Line::Remove(s) => Some((false, *s)),
+            Line::Add(_) => None,
+        })
+        .collect();
+    let leading_context = tagged.iter().take_while(|(is_context, _)| *is_context).count();
+    let trailing_context = tagged.iter().rev().take_while(|(is_context, _)| *is_context).count();
+
+    HunkLines {
+        lines: tagged.into_iter().map(|(_, s)| s).collect(),
+        leading_context,
+        trailing_context,
+    }
+}
+
+/// Whether `from.lines` (or a sub-slice with up to `fuzz` Context lines
+/// trimmed off each end) is present in `old_lines` starting at `pos`. The
+/// amount trimmed off each end is capped by how much Context that end
+/// actually has, so a Remove line is never excluded from the match.
+fn matches_at(old_lines: &[&str], from: &HunkLines<'_>, pos: usize, fuzz: usize) -> bool {
+    let from_lines = &from.lines;
+    let trim_front = fuzz.min(from.leading_context);
+    let trim_back = fuzz.min(from.trailing_context);
+    if trim_front + trim_back >= from_lines.len() {
+        return fuzz == 0 && old_lines.get(pos..pos + from_lines.len()) == Some(from_lines.as_slice());
+    }
+    let trimmed = &from_lines[trim_front..from_lines.len() - trim_back];
+    let start = pos + trim_front;
+    old_lines.get(start..start + trimmed.len()) == Some(trimmed)
+}
+
+/// Searches outward from `guess` (at distance 0, 1, 2, ... up to
+/// `max_offset`, trying both directions at each distance) for a position
+/// where `from` matches, first exactly and then with increasing fuzz.
+#[allow(clippy::as_conversions, clippy::cast_possible_wrap)]
+fn find_hunk(
+    old_lines: &[&str],
+    from: &HunkLines<'_>,
+    guess: isize,
+    max_offset: usize,
+    fuzz: usize,
+) -> Option<usize> {
+    for allowed_fuzz in 0..=fuzz {
+        for d in 0..=max_offset as isize {
+            for candidate in [guess - d, guess + d] {
+                if candidate < 0 {
+                    continue;
+                }
+                let candidate = candidate as usize;
+                if candidate > old_lines.len() {
+                    continue;
+                }
+                if matches_at(old_lines, from, candidate, allowed_fuzz) {
+                    return Some(candidate);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Writes the hunk that failed to apply next to `file_path` with a `.rej`
+/// extension appended, the same convention GNU `patch` uses.
+fn write_reject_file(file_path: &Path, hunk: &patch::Hunk<'_>) -> Result<PathBuf> {
+    let mut reject_name = file_path.as_os_str().to_owned();
+    reject_name.push(".rej");
+    let reject_path = PathBuf::from(reject_name);
+    fs::write(&reject_path, hunk.to_string())?;
+    Ok(reject_path)
+}
+
+#[allow(clippy::as_conversions, clippy::cast_possible_wrap)]
+fn apply_patch(
+    diff: &Patch<'_>,
+    old: &str,
+    file_path: &Path,
+    max_offset: usize,
+    fuzz: usize,
+) -> Result<String> {
     let old_lines = old.lines().collect::<Vec<&str>>();
     let mut out: Vec<&str> = vec![];
-    let mut old_line = 0;
-    for hunk in diff.hunks {
-        while old_line < hunk.old_range.start - 1 {
-            out.push(old_lines[old_line as usize]);
+    let mut old_line: usize = 0;
+    let mut offset: isize = 0;
+    for (index, hunk) in diff.hunks.iter().enumerate() {
+        let from_lines = hunk_from_lines(hunk);
+        let guess = hunk.old_range.start as isize - 1 + offset;
+        let found = match find_hunk(&old_lines, &from_lines, guess, max_offset, fuzz) {
+            Some(found) => found,
+            None => {
+                let reject_path = write_reject_file(file_path, hunk)?;
+                return Err(err_msg(format!(
+                    "Hunk #{} failed to apply to {:?}, rejected hunk written to {:?}",
+                    index + 1,
+                    file_path,
+                    reject_path
+                ))
+                .compat()
+                .into());
+            }
+        };
+        offset = found as isize - guess;
+
+        while old_line < found {
+            out.push(old_lines[old_line]);
             old_line += 1;
         }
-        for line in hunk.lines {
+        for line in &hunk.lines {
             match line {
                 Line::Context(_) => {
-                    if (old_line as usize) < old_lines.len() {
-                        out.push(old_lines[old_line as usize]);
+                    if old_line < old_lines.len() {
+                        out.push(old_lines[old_line]);
                     }
                     old_line += 1;
                 }
@@ -283,13 +674,13 @@ fn apply_patch(diff: Patch<'_>, old: &str) -> String {
             }
         }
     }
-    for line in old_lines.get((old_line as usize)..).unwrap_or(&[]) {
+    for line in old_lines.get(old_line..).unwrap_or(&[]) {
         out.push(line);
     }
     if old.ends_with('\n') {
         out.push("");
     }
-    out.join("\n")
+    Ok(out.join("\n"))
 }
 
 #[allow(clippy::wildcard_enum_match_arm)]
@@ -309,14 +700,15 @@ fn read_to_string(path: &Path) -> Result<String> {
 }
 
 fn main() -> Result<()> {
-    clear_patch_folder()?;
     let config = setup_config()?;
     let _lock = config.acquire_package_cache_lock()?;
     let workspace_path = find_cargo_toml(&PathBuf::from("."))?;
     let workspace = fetch_workspace(&config, &workspace_path)?;
     let (pkg_set, resolve) = resolve_ws(&workspace)?;
 
-    let mut patched = false;
+    let mut previous_state = load_applied_state();
+    let mut next_state = AppliedState::new();
+    let mut patched_paths: HashMap<String, Vec<(String, PathBuf)>> = HashMap::new();
     for member in workspace.members() {
         let patches = get_patches(member);
         let ids = get_ids(patches, &resolve);
@@ -325,22 +717,107 @@ fn main() -> Result<()> {
             .map(|(p, id)| pkg_set.get_one(id).map(|v| (p, v)))
             .collect::<Result<Vec<(PatchEntry, &Package)>>>()?;
         for (patch, package) in packages {
-            let path = copy_package(package)?;
-            patched = true;
-            apply_patches(&patch.name, &patch.patches, &path)?;
+            let version = package.package_id().version().to_string();
+            let source_key = patch_table_key(package.package_id().source_id());
+            let patch_hashes = hash_patch_files(&patch.patches)?;
+            let target_dir = PathBuf::from("target/patch").join(format!("{}-{}", patch.name, version));
+            // Written into Cargo.toml as the `[patch]` override: relative to
+            // the manifest, not the canonicalized, machine-specific path.
+            let override_path = PathBuf::from(".").join(&target_dir);
+            let previous = previous_state.remove(&patch.name);
+            let up_to_date = target_dir.is_dir()
+                && previous.as_ref().is_some_and(|prev| {
+                    prev.version == version && prev.patch_hashes == patch_hashes
+                });
+
+            if up_to_date {
+                patched_paths
+                    .entry(source_key)
+                    .or_default()
+                    .push((patch.name.clone(), override_path));
+                next_state.insert(
+                    patch.name.clone(),
+                    AppliedPatch {
+                        version,
+                        patch_hashes,
+                    },
+                );
+                continue;
+            }
+
+            // Stage the copy+patch in a side directory so a failed re-apply
+            // never touches a `target_dir` that's still serving a good,
+            // previously patched copy (see the `Err(..) if previous.is_some()`
+            // arm below).
+            let staging_dir =
+                PathBuf::from("target/patch").join(format!(".{}-{}.staging", patch.name, version));
+            remove_dir_if_exists(&staging_dir)?;
+            let path = copy_package(package, &staging_dir)?;
+            match apply_patches(&patch.name, &patch.patches, &path) {
+                Ok(()) => {
+                    remove_dir_if_exists(&target_dir)?;
+                    fs::rename(&staging_dir, &target_dir)?;
+                    patched_paths
+                        .entry(source_key)
+                        .or_default()
+                        .push((patch.name.clone(), override_path));
+                    next_state.insert(
+                        patch.name.clone(),
+                        AppliedPatch {
+                            version,
+                            patch_hashes,
+                        },
+                    );
+                }
+                Err(err) if previous.is_some() => {
+                    remove_dir_if_exists(&staging_dir)?;
+                    eprintln!(
+                        "Warning: patch for {} no longer applies to the resolved version {}: {}. \
+                         The build will keep using the previously patched version.",
+                        patch.name, version, err
+                    );
+                    if let Some(previous) = previous {
+                        let previous_path = PathBuf::from(".")
+                            .join("target/patch")
+                            .join(format!("{}-{}", patch.name, previous.version));
+                        patched_paths
+                            .entry(source_key)
+                            .or_default()
+                            .push((patch.name.clone(), previous_path));
+                        next_state.insert(patch.name.clone(), previous);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
         }
     }
-    if !patched {
+    save_applied_state(&next_state)?;
+
+    if patched_paths.is_empty() {
         println!("No patches found");
+    } else {
+        for (source, entries) in &patched_paths {
+            update_patch_overrides(&workspace_path, source, entries)?;
+        }
     }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{apply_patch, RANGE_REGEX, RANGE_REPLACE};
+    use super::{
+        apply_patch, apply_single_file_diff, is_binary_diff_section, resolve_target_path,
+        split_diff_sections, DEFAULT_FUZZ, DEFAULT_MAX_OFFSET, GIT_DIFF_SECTION_REGEX,
+        RANGE_REGEX, RANGE_REPLACE,
+    };
     use patch::Patch;
     use regex::Regex;
+    use std::{fs, path::Path};
+
+    fn apply_patch_default(diff: &Patch<'_>, old: &str) -> String {
+        apply_patch(diff, old, Path::new("test"), DEFAULT_MAX_OFFSET, DEFAULT_FUZZ)
+            .expect("Patch should apply")
+    }
 
     #[test]
     fn apply_patch_simply() {
@@ -368,7 +845,7 @@ This is the patched line
 This is the third line
 "#;
         let patch = Patch::from_single(patch).expect("Unable to parse patch");
-        let test_patched = apply_patch(patch, content);
+        let test_patched = apply_patch_default(&patch, content);
         assert_eq!(patched, test_patched, "Patched content does not match");
     }
 
@@ -413,7 +890,7 @@ culpa qui officia deserunt mollit anim
 id est laborum.
 "#;
         let patch = Patch::from_single(patch).expect("Unable to parse patch");
-        let test_patched = apply_patch(patch, content);
+        let test_patched = apply_patch_default(&patch, content);
         assert_eq!(patched, test_patched, "Patched content does not match");
     }
 
@@ -436,7 +913,7 @@ test4
 test3
 "#;
         let patch = Patch::from_single(patch).expect("Unable to parse patch");
-        let test_patched = apply_patch(patch, content);
+        let test_patched = apply_patch_default(&patch, content);
         assert_eq!(patched, test_patched, "Patched content does not match");
     }
 
@@ -483,7 +960,7 @@ id est laborum.
         let regex = Regex::new(RANGE_REGEX).expect("Failed to parse regex");
         let data = regex.replace_all(patch, RANGE_REPLACE);
         let patch = Patch::from_single(&data).expect("Unable to parse patch");
-        let test_patched = apply_patch(patch, content);
+        let test_patched = apply_patch_default(&patch, content);
         assert_eq!(patched, test_patched, "Patched content does not match");
     }
 
@@ -540,8 +1017,218 @@ id est laborum.
         let patches = Patch::from_multiple(&data).expect("Unable to parse patch");
         let mut test_patched = String::from("");
         for patch in patches {
-            test_patched.push_str(&apply_patch(patch, content));
+            test_patched.push_str(&apply_patch_default(&patch, content));
         }
         assert_eq!(patched, test_patched, "Patched content does not match");
     }
+
+    #[test]
+    fn apply_patch_with_drifted_line_numbers() {
+        // The hunk header claims line 1, but the file has grown three lines
+        // at the top since the patch was made; the context should still be
+        // found by searching outward from the recorded position.
+        let patch = r#"--- test	2020-05-21 08:50:06.629765310 +0200
++++ test	2020-05-21 08:50:19.689878523 +0200
+@@ -1,3 +1,3 @@
+ This is the first line
+-This is the second line
++This is the patched line
+ This is the third line
+"#;
+        let content = r#"A preamble line
+Another preamble line
+A third preamble line
+This is the first line
+This is the second line
+This is the third line
+"#;
+        let patched = r#"A preamble line
+Another preamble line
+A third preamble line
+This is the first line
+This is the patched line
+This is the third line
+"#;
+        let patch = Patch::from_single(patch).expect("Unable to parse patch");
+        let test_patched = apply_patch_default(&patch, content);
+        assert_eq!(patched, test_patched, "Patched content does not match");
+    }
+
+    #[test]
+    fn apply_patch_with_fuzzy_context() {
+        // One of the two context lines around the change no longer matches
+        // the file verbatim; with the default fuzz factor the hunk should
+        // still apply once the context lines are dropped from the match.
+        let patch = r#"--- test	2020-05-21 08:50:06.629765310 +0200
++++ test	2020-05-21 08:50:19.689878523 +0200
+@@ -1,3 +1,3 @@
+ This is the first line
+-This is the second line
++This is the patched line
+ This is the third line
+"#;
+        let content = r#"This is the first line, but slightly different
+This is the second line
+This is the third line
+"#;
+        let patched = r#"This is the first line, but slightly different
+This is the patched line
+This is the third line
+"#;
+        let patch = Patch::from_single(patch).expect("Unable to parse patch");
+        let test_patched = apply_patch_default(&patch, content);
+        assert_eq!(patched, test_patched, "Patched content does not match");
+    }
+
+    #[test]
+    fn apply_patch_fails_and_writes_reject_file() {
+        let patch = r#"--- test	2020-05-21 08:50:06.629765310 +0200
++++ test	2020-05-21 08:50:19.689878523 +0200
+@@ -1,3 +1,3 @@
+ This is the first line
+-This is the second line
++This is the patched line
+ This is the third line
+"#;
+        let content = "Completely unrelated content\nwith no matching context\nat all\n";
+        let patch = Patch::from_single(patch).expect("Unable to parse patch");
+        let file_path = std::env::temp_dir().join("cargo_patch_reject_test.txt");
+        let result = apply_patch(&patch, content, &file_path, DEFAULT_MAX_OFFSET, DEFAULT_FUZZ);
+        assert!(result.is_err(), "Patch should have failed to apply");
+        let reject_path = std::env::temp_dir().join("cargo_patch_reject_test.txt.rej");
+        assert!(reject_path.exists(), "Reject file should have been written");
+        let _ = fs::remove_file(reject_path);
+    }
+
+    #[test]
+    fn split_diff_sections_splits_per_file() {
+        let data = r#"diff --git a/foo.rs b/foo.rs
+index 1111111..2222222 100644
+--- a/foo.rs
++++ b/foo.rs
+@@ -1,1 +1,1 @@
+-old
++new
+diff --git a/bar.rs b/bar.rs
+index 3333333..4444444 100644
+--- a/bar.rs
++++ b/bar.rs
+@@ -1,1 +1,1 @@
+-old
++new
+"#;
+        let marker = Regex::new(GIT_DIFF_SECTION_REGEX).expect("valid regex");
+        let sections = split_diff_sections(data, &marker);
+        assert_eq!(sections.len(), 2);
+        assert!(sections[0].contains("foo.rs"));
+        assert!(!sections[0].contains("bar.rs"));
+        assert!(sections[1].contains("bar.rs"));
+    }
+
+    #[test]
+    fn split_diff_sections_without_git_header_is_single_section() {
+        let data = "--- test\n+++ test\n@@ -1,1 +1,1 @@\n-old\n+new\n";
+        let marker = Regex::new(GIT_DIFF_SECTION_REGEX).expect("valid regex");
+        let sections = split_diff_sections(data, &marker);
+        assert_eq!(sections, vec![data]);
+    }
+
+    #[test]
+    fn is_binary_diff_section_detects_binary_marker() {
+        let section = "diff --git a/img.png b/img.png\nBinary files a/img.png and b/img.png differ\n";
+        assert!(is_binary_diff_section(section));
+        let text_section = "diff --git a/foo.rs b/foo.rs\n--- a/foo.rs\n+++ b/foo.rs\n";
+        assert!(!is_binary_diff_section(text_section));
+    }
+
+    #[test]
+    fn resolve_target_path_rejects_escape() {
+        let dir = std::env::temp_dir().join("cargo_patch_resolve_test");
+        fs::create_dir_all(&dir).expect("Unable to create test dir");
+        let result = resolve_target_path(&dir, "../../etc/passwd");
+        assert!(result.is_err(), "Escaping path should be rejected");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn resolve_target_path_strips_git_prefix() {
+        let dir = std::env::temp_dir().join("cargo_patch_resolve_test_prefix");
+        fs::create_dir_all(&dir).expect("Unable to create test dir");
+        let resolved = resolve_target_path(&dir, "a/src/lib.rs").expect("Should resolve");
+        assert_eq!(resolved, dir.canonicalize().expect("canonicalize").join("src/lib.rs"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn apply_single_file_diff_creates_file() {
+        let dir = std::env::temp_dir().join("cargo_patch_apply_create_test");
+        fs::create_dir_all(&dir).expect("Unable to create test dir");
+
+        let section = r#"diff --git a/new_file.rs b/new_file.rs
+new file mode 100644
+index 0000000..1111111
+--- /dev/null
++++ b/new_file.rs
+@@ -0,0 +1,2 @@
++line one
++line two
+"#;
+        let diff = Patch::from_single(section).expect("Unable to parse patch");
+        apply_single_file_diff("test", &diff, &dir).expect("Create should apply");
+
+        let created = fs::read_to_string(dir.join("new_file.rs")).expect("File should be created");
+        assert_eq!(created, "line one\nline two");
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn apply_single_file_diff_deletes_file() {
+        let dir = std::env::temp_dir().join("cargo_patch_apply_delete_test");
+        fs::create_dir_all(&dir).expect("Unable to create test dir");
+        fs::write(dir.join("old_file.rs"), "line one\nline two\n").expect("Unable to write fixture");
+
+        let section = r#"diff --git a/old_file.rs b/old_file.rs
+deleted file mode 100644
+index 1111111..0000000
+--- a/old_file.rs
++++ /dev/null
+@@ -1,2 +0,0 @@
+-line one
+-line two
+"#;
+        let diff = Patch::from_single(section).expect("Unable to parse patch");
+        apply_single_file_diff("test", &diff, &dir).expect("Delete should apply");
+
+        assert!(!dir.join("old_file.rs").exists(), "File should have been deleted");
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn apply_single_file_diff_renames_with_edit() {
+        let dir = std::env::temp_dir().join("cargo_patch_apply_rename_test");
+        fs::create_dir_all(&dir).expect("Unable to create test dir");
+        fs::write(dir.join("old_name.rs"), "old content\n").expect("Unable to write fixture");
+
+        let section = r#"diff --git a/old_name.rs b/new_name.rs
+similarity index 90%
+rename from old_name.rs
+rename to new_name.rs
+index 1111111..2222222 100644
+--- a/old_name.rs
++++ b/new_name.rs
+@@ -1 +1 @@
+-old content
++new content
+"#;
+        let diff = Patch::from_single(section).expect("Unable to parse patch");
+        apply_single_file_diff("test", &diff, &dir).expect("Rename with edit should apply");
+
+        assert!(!dir.join("old_name.rs").exists(), "Old path should no longer exist");
+        let renamed = fs::read_to_string(dir.join("new_name.rs")).expect("New path should exist");
+        assert_eq!(renamed, "new content\n");
+
+        let _ = fs::remove_dir_all(dir);
+    }
 }